@@ -1,24 +1,67 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
 
-use chrono::NaiveDateTime;
+use chrono::{FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
 use openssh::{KnownHosts, Session};
-use regex::{Captures, Regex};
+use regex::{Captures, Regex, RegexSet};
+use serde::{Deserialize, Serialize};
 use strfmt::strfmt;
 use termion::{color, style};
 
-#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+}
+
+impl Level {
+    pub fn parse(raw: &str, synonyms: &HashMap<String, Level>) -> Level {
+        let key = raw.trim().to_ascii_uppercase();
+        if let Some(level) = synonyms.get(&key) {
+            return *level;
+        }
+        match key.as_str() {
+            "TRACE" => Level::Trace,
+            "DEBUG" => Level::Debug,
+            "INFO" => Level::Info,
+            "WARN" | "WARNING" => Level::Warn,
+            "ERROR" | "ERR" => Level::Error,
+            "FATAL" | "CRIT" | "CRITICAL" => Level::Fatal,
+            _ => Level::Info,
+        }
+    }
+
+    fn color(&self) -> String {
+        match self {
+            Level::Error | Level::Fatal => color::Fg(color::Red).to_string(),
+            Level::Warn => color::Fg(color::Yellow).to_string(),
+            Level::Trace | Level::Debug => style::Faint.to_string(),
+            Level::Info => color::Fg(color::Reset).to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd, Hash, Serialize, Deserialize)]
 pub(crate) struct LogLine {
     timestamp: i64,
     hostname: String,
     service: String,
+    level: Level,
     pub(crate) message: String,
 }
 
 impl LogLine {
-    pub fn new(timestamp: i64, hostname: String, service: String, message: String) -> LogLine {
-        LogLine { timestamp, hostname, service, message }
+    pub fn new(timestamp: i64, hostname: String, service: String, level: Level, message: String) -> LogLine {
+        LogLine { timestamp, hostname, service, level, message }
     }
 }
 
@@ -38,6 +81,10 @@ impl Tracer for LogLine {
     fn message(&self) -> String {
         self.message.clone()
     }
+
+    fn level(&self) -> Level {
+        self.level
+    }
 }
 
 pub(crate) struct Logs {
@@ -45,6 +92,13 @@ pub(crate) struct Logs {
     line_idx: usize,
 }
 
+pub enum GroupKey {
+    Hostname,
+    Service,
+    Level,
+    Message(Regex),
+}
+
 impl Logs {
     pub fn new(lines: Vec<LogLine>) -> Logs {
         Logs { lines, line_idx: 0 }
@@ -57,10 +111,64 @@ impl Logs {
         }
     }
 
-    pub fn merge(&mut self, other: Self) -> Self {
+    pub fn merge(&mut self, other: Self, dedup: Option<i64>) -> Self {
         let mut lines = [&self.lines[..], &other.lines[..]].concat();
         lines.sort();
-        Self::new(lines)
+        let merged = Self::new(lines);
+        match dedup {
+            Some(window_micros) => merged.dedup(window_micros),
+            None => merged,
+        }
+    }
+
+    /// Requires `self.lines` to be sorted by timestamp (as produced by
+    /// `merge`); the age window is pruned assuming ascending timestamps.
+    pub fn dedup(&self, window_micros: i64) -> Logs {
+        debug_assert!(self.lines.windows(2).all(|w| w[0].timestamp <= w[1].timestamp),
+                      "dedup requires lines sorted by timestamp");
+        let mut window: VecDeque<LogLine> = VecDeque::new();
+        let mut seen: HashSet<LogLine> = HashSet::new();
+        let mut lines: Vec<LogLine> = Vec::new();
+
+        for line in self.lines.iter() {
+            while let Some(front) = window.front() {
+                if front.timestamp < line.timestamp - window_micros {
+                    let old = window.pop_front().unwrap();
+                    seen.remove(&old);
+                } else {
+                    break;
+                }
+            }
+
+            if seen.contains(line) {
+                continue;
+            }
+
+            lines.push(line.clone());
+            window.push_back(line.clone());
+            seen.insert(line.clone());
+        }
+
+        Logs { lines, line_idx: 0 }
+    }
+
+    pub fn filter_regex(&self, exclude: &[Regex], include: &[Regex]) -> Logs {
+        let include_set = RegexSet::new(include.iter().map(|r| r.as_str())).unwrap();
+        let exclude_set = RegexSet::new(exclude.iter().map(|r| r.as_str())).unwrap();
+
+        let lines: Vec<LogLine> = self.lines.iter().cloned()
+            .filter(|x| x.includes_regex(&include_set))
+            .filter(|x| !x.excludes_regex(&exclude_set))
+            .collect();
+
+        Logs { lines, line_idx: 0 }
+    }
+
+    pub fn write_all<F: Formatter>(&self, fmt: &F, out: &mut dyn Write) -> io::Result<()> {
+        for line in self.lines.iter() {
+            fmt.encode(line, out)?;
+        }
+        Ok(())
     }
 
     pub fn filter_logs(&self, exclude: &Vec<String>, include: &Vec<String>) -> Logs {
@@ -71,6 +179,58 @@ impl Logs {
 
         Logs { lines, line_idx: 0 }
     }
+
+    /// Requires `self.lines` to be sorted by timestamp (as produced by
+    /// `merge`); the binary search returns a wrong slice otherwise.
+    pub fn filter_time_range(&self, start: Option<NaiveDateTime>, end: Option<NaiveDateTime>) -> Logs {
+        debug_assert!(self.lines.windows(2).all(|w| w[0].timestamp <= w[1].timestamp),
+                      "filter_time_range requires lines sorted by timestamp");
+        let start_idx = match start {
+            Some(start) => {
+                let micros = start.timestamp() * 1000000 + start.timestamp_subsec_micros() as i64;
+                self.lines.partition_point(|x| x.timestamp < micros)
+            }
+            None => 0,
+        };
+        let end_idx = match end {
+            Some(end) => {
+                let micros = end.timestamp() * 1000000 + end.timestamp_subsec_micros() as i64;
+                self.lines.partition_point(|x| x.timestamp <= micros)
+            }
+            None => self.lines.len(),
+        };
+
+        let lines = self.lines[start_idx..end_idx].to_vec();
+        Logs { lines, line_idx: 0 }
+    }
+
+    pub fn frequency(&self, by: GroupKey) -> Vec<(String, u64)> {
+        let mut counts: HashMap<String, u64> = HashMap::new();
+        for line in self.lines.iter() {
+            let key = match &by {
+                GroupKey::Hostname => Some(line.hostname.clone()),
+                GroupKey::Service => Some(line.service.clone()),
+                GroupKey::Level => Some(format!("{:?}", line.level)),
+                GroupKey::Message(re) => re.captures(&line.message)
+                    .and_then(|caps| caps.get(1).or_else(|| caps.get(0)).map(|m| m.as_str().to_string())),
+            };
+            if let Some(key) = key {
+                *counts.entry(key).or_insert(0) += 1;
+            }
+        }
+
+        let mut histogram: Vec<(String, u64)> = counts.into_iter().collect();
+        histogram.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        histogram
+    }
+
+    pub fn filter_level(&self, min: Level) -> Logs {
+        let lines: Vec<LogLine> = self.lines.iter().cloned()
+            .filter(|x| x.level >= min)
+            .collect();
+
+        Logs { lines, line_idx: 0 }
+    }
 }
 
 impl Iterator for Logs {
@@ -93,9 +253,12 @@ pub trait Tracer {
     fn service(&self) -> String;
     fn hostname(&self) -> String;
     fn message(&self) -> String;
+    fn level(&self) -> Level {
+        Level::Info
+    }
     fn header(&self) -> String {
         format!("{color}{unit}@{host} -- [{datetime}]{style_reset}",
-                color = color::Fg(color::Yellow),
+                color = self.level().color(),
                 style_reset = style::Reset,
                 unit = self.service(),
                 host = self.hostname(),
@@ -111,6 +274,14 @@ pub trait Tracer {
         words.iter().any(|word| self.message().contains(word))
     }
 
+    fn includes_regex(&self, patterns: &RegexSet) -> bool {
+        patterns.matches(&self.message()).iter().count() == patterns.len()
+    }
+
+    fn excludes_regex(&self, patterns: &RegexSet) -> bool {
+        patterns.is_match(&self.message())
+    }
+
     fn print_line(&self) {
         println!("{header}\n\t{msg}\n\n",
                  header = self.header(),
@@ -123,6 +294,90 @@ pub(crate) trait LogSource {
     fn lines(&self) -> Vec<LogLine>;
 }
 
+pub trait Formatter {
+    fn encode(&self, line: &LogLine, out: &mut dyn Write) -> io::Result<()>;
+}
+
+pub trait Decode {
+    fn decode(&self, input: &mut dyn Read) -> io::Result<Vec<LogLine>>;
+}
+
+pub struct PrettyFormatter;
+
+impl Formatter for PrettyFormatter {
+    fn encode(&self, line: &LogLine, out: &mut dyn Write) -> io::Result<()> {
+        write!(out, "{header}\n\t{msg}\n\n", header = line.header(), msg = line.message())
+    }
+}
+
+pub struct JsonFormatter;
+
+#[derive(Serialize, Deserialize)]
+struct JsonLine {
+    timestamp: i64,
+    hostname: String,
+    service: String,
+    message: String,
+}
+
+impl Formatter for JsonFormatter {
+    fn encode(&self, line: &LogLine, out: &mut dyn Write) -> io::Result<()> {
+        let record = JsonLine {
+            timestamp: line.timestamp,
+            hostname: line.hostname.clone(),
+            service: line.service.clone(),
+            message: line.message.clone(),
+        };
+        serde_json::to_writer(&mut *out, &record).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        out.write_all(b"\n")
+    }
+}
+
+impl Decode for JsonFormatter {
+    fn decode(&self, input: &mut dyn Read) -> io::Result<Vec<LogLine>> {
+        let mut buf = String::new();
+        input.read_to_string(&mut buf)?;
+        let mut lines = Vec::new();
+        for raw in buf.lines() {
+            if raw.trim().is_empty() {
+                continue;
+            }
+            let record: JsonLine = serde_json::from_str(raw).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            lines.push(LogLine::new(record.timestamp, record.hostname, record.service, Level::Info, record.message));
+        }
+        Ok(lines)
+    }
+}
+
+pub struct MsgPackFormatter;
+
+impl Formatter for MsgPackFormatter {
+    fn encode(&self, line: &LogLine, out: &mut dyn Write) -> io::Result<()> {
+        rmp_serde::encode::write(out, line).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+impl Decode for MsgPackFormatter {
+    fn decode(&self, input: &mut dyn Read) -> io::Result<Vec<LogLine>> {
+        let mut buf = Vec::new();
+        input.read_to_end(&mut buf)?;
+        let mut de = rmp_serde::Deserializer::new(&buf[..]);
+        let mut lines = Vec::new();
+        loop {
+            match LogLine::deserialize(&mut de) {
+                Ok(line) => lines.push(line),
+                Err(rmp_serde::decode::Error::InvalidMarkerRead(ref e))
+                    if e.kind() == io::ErrorKind::UnexpectedEof =>
+                {
+                    break
+                }
+                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            }
+        }
+        Ok(lines)
+    }
+}
+
 
 pub(super) fn read_proc(process: &str, args: &[&str]) -> Result<String, Box<dyn Error>> {
     let ps = Command::new(process)
@@ -147,12 +402,116 @@ pub(super) async fn read_remote_proc(process: &str, args: &[&str], addr: &str) -
     Ok(output)
 }
 
+pub(super) fn follow_proc(process: &str, args: &[&str]) -> Result<Receiver<String>, Box<dyn Error>> {
+    let mut child = Command::new(process)
+        .stdout(Stdio::piped())
+        .args(args)
+        .spawn()?;
+    let stdout = child.stdout.take().ok_or("child stdout was not captured")?;
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let reader = BufReader::new(stdout);
+        for line in reader.lines().flatten() {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+        let _ = child.wait();
+    });
+
+    Ok(rx)
+}
+
+pub(super) fn follow_remote_proc(process: &str, args: &[&str], addr: &str) -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    let process = process.to_string();
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    let addr = addr.to_string();
+
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Runtime::new() {
+            Ok(runtime) => runtime,
+            Err(_) => return,
+        };
+        let _ = runtime.block_on(stream_remote_proc(&process, &args, &addr, tx));
+    });
+
+    rx
+}
+
+async fn stream_remote_proc(process: &str, args: &[String], addr: &str, tx: Sender<String>) -> Result<(), Box<dyn Error>> {
+    use tokio::io::AsyncBufReadExt;
+
+    let session = Session::connect(addr, KnownHosts::Strict).await?;
+    let mut child = session.command(process.to_string())
+        .args(args.iter().map(|a| a.as_str()).collect::<Vec<_>>())
+        .stdout(openssh::Stdio::piped())
+        .spawn()
+        .await?;
+    let stdout = child.stdout().take().ok_or("remote child stdout was not captured")?;
+
+    let mut lines = tokio::io::BufReader::new(stdout).lines();
+    while let Some(line) = lines.next_line().await? {
+        if tx.send(line).is_err() {
+            break;
+        }
+    }
+
+    child.wait().await?;
+    session.close().await?;
+    Ok(())
+}
+
+pub(crate) struct LogStream {
+    rx: Receiver<String>,
+    extractor: RegExtractor,
+    buffer: BinaryHeap<Reverse<LogLine>>,
+    capacity: usize,
+}
+
+impl LogStream {
+    pub fn new(rx: Receiver<String>, extractor: RegExtractor, capacity: usize) -> LogStream {
+        LogStream { rx, extractor, buffer: BinaryHeap::new(), capacity }
+    }
+}
+
+impl Iterator for LogStream {
+    type Item = LogLine;
+    fn next(&mut self) -> Option<Self::Item> {
+        // Hold up to `capacity` lines in a timestamp-ordered reorder buffer so
+        // that lines arriving slightly out of order across hosts are emitted on
+        // a single ordered view. Once the buffer is full we release its oldest
+        // line; when the channel closes we drain whatever remains in order.
+        while self.buffer.len() <= self.capacity {
+            match self.rx.recv() {
+                Ok(raw) => {
+                    if let Some(line) = self.extractor.extract(&raw) {
+                        self.buffer.push(Reverse(line));
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        self.buffer.pop().map(|Reverse(line)| line)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub timezone: FixedOffset,
+    pub override_date: Option<NaiveDate>,
+}
+
 #[derive(Debug)]
 pub struct RegExtractor {
     datetime: String,
     host: String,
     service: String,
     message: String,
+    level: Option<String>,
+    synonyms: HashMap<String, Level>,
+    context: Context,
     line_pattern: String,
     regex: Regex,
     pub(crate) split_pattern: String,
@@ -164,19 +523,24 @@ pub(crate) struct LogScheme {
     pub(crate) host: String,
     pub(crate) service: String,
     pub(crate) message: String,
+    pub(crate) level: Option<String>,
+    pub(crate) level_synonyms: HashMap<String, Level>,
     pub(crate) whole_line: String,
     pub (crate) split_pattern: String
 }
 
 #[allow(dead_code)]
 impl RegExtractor {
-    pub(crate) fn new(scheme: LogScheme, strftime_pattern: &str) -> RegExtractor {
+    pub(crate) fn new(scheme: LogScheme, strftime_pattern: &str, context: Context) -> RegExtractor {
         let mut vars = HashMap::new();
 
         vars.insert("d".to_string(), &scheme.date_time);
         vars.insert("h".to_string(), &scheme.host);
         vars.insert("s".to_string(), &scheme.service);
         vars.insert("m".to_string(), &scheme.message);
+        if let Some(ref level) = scheme.level {
+            vars.insert("l".to_string(), level);
+        }
 
         let formated_log_pattern = strfmt(&scheme.whole_line, &vars).unwrap();
         let re = Regex::new(&formated_log_pattern).unwrap();
@@ -186,6 +550,9 @@ impl RegExtractor {
             host: scheme.host.clone(),
             service: scheme.service.clone(),
             message: scheme.message.clone(),
+            level: scheme.level.clone(),
+            synonyms: scheme.level_synonyms.clone(),
+            context,
             line_pattern: formated_log_pattern,
             regex: re,
             split_pattern: scheme.split_pattern,
@@ -198,10 +565,39 @@ impl RegExtractor {
         captures
     }
 
-    pub fn timestamp_micros(&self, strftime: &str) -> i64 {
-        let date_time = NaiveDateTime::parse_from_str(strftime, &self.strftime_pattern).unwrap();
-        let timestamp = date_time.timestamp() * 1000000 + date_time.timestamp_subsec_micros() as i64;
-        timestamp
+    pub fn extract(&self, logline: &String) -> Option<LogLine> {
+        let captures = self.get_fields(logline)?;
+        let timestamp = self.timestamp_micros(captures.name("d")?.as_str())?;
+        let hostname = captures.name("h").map(|m| m.as_str().to_string()).unwrap_or_default();
+        let service = captures.name("s").map(|m| m.as_str().to_string()).unwrap_or_default();
+        let message = captures.name("m").map(|m| m.as_str().to_string()).unwrap_or_default();
+        let level = self.level(&captures);
+        Some(LogLine::new(timestamp, hostname, service, level, message))
+    }
+
+    pub fn level(&self, captures: &Captures) -> Level {
+        match self.level {
+            Some(_) => captures.name("l")
+                .map(|m| Level::parse(m.as_str(), &self.synonyms))
+                .unwrap_or(Level::Info),
+            None => Level::Info,
+        }
+    }
+
+    pub fn timestamp_micros(&self, strftime: &str) -> Option<i64> {
+        let naive = match NaiveDateTime::parse_from_str(strftime, &self.strftime_pattern) {
+            Ok(date_time) => date_time,
+            Err(_) => {
+                let time = NaiveTime::parse_from_str(strftime, &self.strftime_pattern).ok()?;
+                let date = self.context.override_date?;
+                date.and_time(time)
+            }
+        };
+        let date_time = self.context.timezone
+            .from_local_datetime(&naive)
+            .single()?
+            .naive_utc();
+        Some(date_time.timestamp() * 1000000 + date_time.timestamp_subsec_micros() as i64)
     }
 }
 
@@ -220,3 +616,69 @@ pub fn split_keep<'a>(r: &Regex, text: &'a str) -> Vec<&'a str> {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(timestamp: i64, message: &str) -> LogLine {
+        LogLine::new(timestamp, "host".to_string(), "svc".to_string(), Level::Info, message.to_string())
+    }
+
+    #[test]
+    fn dedup_suppresses_near_simultaneous_duplicates_only() {
+        // Two identical "dup" lines at the same instant plus a far-apart repeat.
+        let lines = vec![
+            line(1_000_000, "dup"),
+            line(1_000_000, "dup"),
+            line(2_000_000, "other"),
+            line(60_000_000, "dup"),
+        ];
+
+        let deduped: Vec<LogLine> = Logs::new(lines).dedup(5_000_000).collect();
+
+        // The simultaneous duplicate is dropped; the repeat 59s later survives.
+        let messages: Vec<String> = deduped.iter().map(|l| l.message.clone()).collect();
+        assert_eq!(messages, vec!["dup", "other", "dup"]);
+    }
+
+    #[test]
+    fn filter_time_range_bounds_are_inclusive() {
+        let lines = vec![line(1_000_000, "a"), line(2_000_000, "b"), line(3_000_000, "c")];
+
+        let from = NaiveDateTime::from_timestamp(2, 0);
+        let until = NaiveDateTime::from_timestamp(3, 0);
+        let kept: Vec<LogLine> = Logs::new(lines).filter_time_range(Some(from), Some(until)).collect();
+
+        let timestamps: Vec<i64> = kept.iter().map(|l| l.timestamp).collect();
+        assert_eq!(timestamps, vec![2_000_000, 3_000_000]);
+    }
+
+    #[test]
+    fn msgpack_round_trips_losslessly() {
+        let lines = vec![
+            LogLine::new(1_000_000, "alpha".to_string(), "sshd".to_string(), Level::Error, "boom".to_string()),
+            LogLine::new(2_000_000, "beta".to_string(), "cron".to_string(), Level::Debug, "tick".to_string()),
+        ];
+
+        let mut buf = Vec::new();
+        Logs::new(lines.clone()).write_all(&MsgPackFormatter, &mut buf).unwrap();
+
+        let decoded = MsgPackFormatter.decode(&mut &buf[..]).unwrap();
+        assert_eq!(decoded, lines);
+    }
+
+    #[test]
+    fn json_round_trips_the_four_documented_fields() {
+        let line = LogLine::new(1_500_000, "alpha".to_string(), "sshd".to_string(), Level::Warn, "nope".to_string());
+
+        let mut buf = Vec::new();
+        Logs::new(vec![line.clone()]).write_all(&JsonFormatter, &mut buf).unwrap();
+
+        // `level` is intentionally absent from the JSON encoding, so it comes
+        // back as the default `Info` rather than the original `Warn`.
+        let decoded = JsonFormatter.decode(&mut &buf[..]).unwrap();
+        let expected = LogLine::new(1_500_000, "alpha".to_string(), "sshd".to_string(), Level::Info, "nope".to_string());
+        assert_eq!(decoded, vec![expected]);
+    }
+}